@@ -1,13 +1,22 @@
+use nix::errno::Errno;
 use nix::mount::{mount, umount, MsFlags};
 use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd;
+use serde::Deserialize;
+use std::convert::TryFrom;
 use std::env;
 use std::fs;
 use std::fs::Permissions;
 use std::io;
 use std::io::prelude::*;
+use std::mem;
 use std::os::unix::fs::symlink;
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::path::PathBuf;
@@ -121,7 +130,415 @@ fn bind_mount_direntry(entry: io::Result<fs::DirEntry>) {
     }
 }
 
-fn run_chroot(nixdir: &Path, cmd: &str, args: &[String]) {
+/// Probes whether the running kernel allows an unprivileged overlay mount
+/// inside the current user/mount namespace (available since Linux 5.11).
+/// Used to pick between the single-mount overlay store and the legacy
+/// per-entry bind mount loop.
+fn overlay_supported() -> bool {
+    let probe = match TempDir::new() {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let lower = probe.path().join("lower");
+    let merged = probe.path().join("merged");
+    if fs::create_dir(&lower).is_err() || fs::create_dir(&merged).is_err() {
+        return false;
+    }
+    let opts = format!("lowerdir={}", lower.display());
+    match mount(
+        Some("overlay"),
+        &merged,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(opts.as_str()),
+    ) {
+        Ok(()) => {
+            let _ = umount(&merged);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Merges `nixdir` over `old_store` into `/nix/store` with a single overlay
+/// mount. `nixdir` is listed first in `lowerdir` so it takes precedence,
+/// matching the merge order of the per-entry bind mount loop below.
+///
+/// `overlay_supported` only probes a single `lowerdir`; a two-lowerdir
+/// overlay can still be rejected by the kernel, so this returns the mount
+/// error instead of panicking and lets the caller fall back to
+/// `mount_store_legacy`.
+fn mount_store_overlay(nixdir: &Path, old_store: &Path) -> nix::Result<()> {
+    let opts = format!("lowerdir={}:{}", nixdir.display(), old_store.display());
+    mount(
+        Some("overlay"),
+        "/nix/store",
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(opts.as_str()),
+    )
+}
+
+/// Fallback for kernels without unprivileged overlay support: recreates
+/// `nixdir`'s entries under a tmpfs at `/nix/store` and bind mounts each one
+/// individually, filling in anything missing from `old_store`.
+fn mount_store_legacy(nixdir: &Path, old_store: &Path) {
+    mount(
+        Some("none"),
+        "/nix/store",
+        Some("tmpfs"),
+        MsFlags::empty(),
+        Some("mode=0755"),
+    )
+    .unwrap();
+    let sroot = PathBuf::from("/nix/store");
+    for entry in fs::read_dir(nixdir).unwrap() {
+        let entry = entry.unwrap();
+        let stat = entry.metadata().unwrap();
+        let name = entry.file_name();
+        let path = sroot.join(&name);
+        let store_path = entry.path();
+        if stat.is_dir() {
+            fs::create_dir(&path).unwrap();
+        } else if stat.is_file() {
+            fs::File::create(&path).unwrap();
+        } else if stat.file_type().is_symlink() {
+            let target = fs::read_link(&store_path).unwrap();
+            symlink(&target, &path).unwrap();
+        }
+        if stat.is_dir() || stat.is_file() {
+            mount(
+                Some(&store_path),
+                &path,
+                Some("none"),
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            )
+            .unwrap();
+        }
+    }
+    if let Ok(iter) = fs::read_dir(old_store) {
+        for entry in iter {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+            let path = sroot.join(&name);
+            if path.exists() {
+                continue;
+            }
+            let stat = entry.metadata().unwrap();
+            let store_path = entry.path();
+            if stat.is_dir() {
+                fs::create_dir(&path).unwrap();
+            } else if stat.is_file() {
+                fs::File::create(&path).unwrap();
+            } else if stat.file_type().is_symlink() {
+                let target = fs::read_link(&store_path).unwrap();
+                symlink(&target, &path).unwrap();
+            }
+            if stat.is_dir() || stat.is_file() {
+                mount(
+                    Some(&store_path),
+                    &path,
+                    Some("none"),
+                    MsFlags::MS_BIND | MsFlags::MS_REC,
+                    NONE,
+                )
+                .unwrap();
+            }
+        }
+    }
+    mount(
+        Some("none"),
+        "/nix/store",
+        Some("none"),
+        MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        NONE,
+    )
+    .unwrap();
+}
+
+/// A single entry of a user-supplied mount spec, applied inside the chroot
+/// after `pivot_root`. Mirrors the `CustomMount` model used by container
+/// tooling: a tagged union keyed on `type`, read from JSON via `serde_json`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum MountSpecEntry {
+    Bind {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    RoBind {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    Tmpfs {
+        destination: PathBuf,
+        options: Option<String>,
+    },
+    Overlay {
+        destination: PathBuf,
+        options: String,
+    },
+    Symlink {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+}
+
+/// Creates `destination` as the right kind of mountpoint for `source`
+/// (mirroring `bind_mount_directory`/`bind_mount_file`) and bind mounts it.
+fn bind_mount_spec_entry(source: &Path, destination: &Path) {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    let is_dir = fs::metadata(source)
+        .unwrap_or_else(|_| panic!("cannot stat mount spec source {}", source.display()))
+        .is_dir();
+    if is_dir {
+        fs::create_dir_all(destination)
+            .unwrap_or_else(|_| panic!("failed to create {}", destination.display()));
+    } else {
+        fs::File::create(destination)
+            .unwrap_or_else(|_| panic!("failed to create {}", destination.display()));
+    }
+    bind_mount(source, destination);
+}
+
+fn apply_mount_spec_entry(entry: &MountSpecEntry) {
+    match entry {
+        MountSpecEntry::Bind {
+            source,
+            destination,
+        } => {
+            bind_mount_spec_entry(source, destination);
+        }
+        MountSpecEntry::RoBind {
+            source,
+            destination,
+        } => {
+            bind_mount_spec_entry(source, destination);
+            mount(
+                NONE,
+                destination,
+                NONE,
+                MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_BIND,
+                NONE,
+            )
+            .unwrap_or_else(|e| {
+                panic!("failed to remount {} read-only: {}", destination.display(), e)
+            });
+        }
+        MountSpecEntry::Tmpfs {
+            destination,
+            options,
+        } => {
+            fs::create_dir_all(destination)
+                .unwrap_or_else(|_| panic!("failed to create {}", destination.display()));
+            mount(
+                Some("none"),
+                destination,
+                Some("tmpfs"),
+                MsFlags::empty(),
+                options.as_deref(),
+            )
+            .unwrap_or_else(|e| panic!("failed to mount tmpfs at {}: {}", destination.display(), e));
+        }
+        MountSpecEntry::Overlay {
+            destination,
+            options,
+        } => {
+            fs::create_dir_all(destination)
+                .unwrap_or_else(|_| panic!("failed to create {}", destination.display()));
+            mount(
+                Some("overlay"),
+                destination,
+                Some("overlay"),
+                MsFlags::empty(),
+                Some(options.as_str()),
+            )
+            .unwrap_or_else(|e| panic!("failed to mount overlay at {}: {}", destination.display(), e));
+        }
+        MountSpecEntry::Symlink {
+            source,
+            destination,
+        } => {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent).ok();
+            }
+            symlink(source, destination).unwrap_or_else(|_| {
+                panic!(
+                    "failed to create symlink {} -> {}",
+                    destination.display(),
+                    source.display()
+                )
+            });
+        }
+    }
+}
+
+/// Reads `spec_path` as a JSON array of [`MountSpecEntry`] and applies each
+/// one in order. Called after `pivot_root` and before `exec`, so entries may
+/// reference paths set up earlier (e.g. `/nix/store`).
+fn apply_mount_spec(spec_path: &Path) {
+    let data = fs::read_to_string(spec_path)
+        .unwrap_or_else(|e| panic!("failed to read mount spec {}: {}", spec_path.display(), e));
+    let entries: Vec<MountSpecEntry> = serde_json::from_str(&data)
+        .unwrap_or_else(|e| panic!("failed to parse mount spec {}: {}", spec_path.display(), e));
+    for entry in &entries {
+        apply_mount_spec_entry(entry);
+    }
+}
+
+/// Reaps every exited child, and once `child` itself (the command we exec'd)
+/// is among them, exits with its exit code, or with 128 plus the number of
+/// the signal that killed it (the signal itself is not re-raised).
+fn reap_children(child: unistd::Pid) {
+    loop {
+        match waitpid(unistd::Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(pid, code)) => {
+                if pid == child {
+                    process::exit(code);
+                }
+            }
+            Ok(WaitStatus::Signaled(pid, sig, _)) => {
+                if pid == child {
+                    process::exit(128 + sig as i32);
+                }
+            }
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(_) => continue,
+            Err(Errno::ECHILD) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => panic!("waitpid failed: {}", e),
+        }
+    }
+}
+
+/// Runs as PID 1 of the namespace `run_chroot` creates (we are reached via
+/// the first fork after `unshare(CLONE_NEWPID)`, so the kernel assigns us
+/// that PID). Forks and execs `cmd` as our own child, then reaps zombies on
+/// `SIGCHLD` and forwards SIGINT/SIGTERM/SIGHUP to its process group, giving
+/// the chroot correct subreaper semantics for multi-process workloads.
+fn run_init(cmd: &str, args: &[String], target_cwd: &Path, opts: &Options) -> ! {
+    // `/proc` so far is still the host's recursive bind mount from
+    // `bind_mount_direntry`, so it would report host PIDs. Now that we are
+    // PID 1 of the new namespace, mount a fresh `proc` over it so the
+    // namespace is self-consistent for anything that reads `/proc/<pid>`.
+    mount(
+        Some("proc"),
+        "/proc",
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        NONE,
+    )
+    .unwrap_or_else(|e| panic!("failed to mount /proc: {}", e));
+
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGCHLD);
+    mask.add(Signal::SIGINT);
+    mask.add(Signal::SIGTERM);
+    mask.add(Signal::SIGHUP);
+    // block before forking: execve preserves the signal mask, so the command
+    // process unblocks it again for itself below. Blocking first means a
+    // child that exits before the signalfd is even read leaves SIGCHLD
+    // pending instead of discarded, so we can't miss it and hang forever.
+    mask.thread_block().expect("failed to block signals in init");
+
+    let mut sigfd =
+        SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC).expect("failed to create signalfd");
+
+    let child = match unsafe { unistd::fork() }.expect("failed to fork command process") {
+        unistd::ForkResult::Parent { child } => child,
+        unistd::ForkResult::Child => {
+            mask.thread_unblock().expect("failed to unblock signals");
+            unistd::setpgid(unistd::Pid::from_raw(0), unistd::Pid::from_raw(0))
+                .expect("failed to set process group");
+
+            env::set_current_dir(target_cwd).unwrap_or_else(|_| {
+                panic!("cannot set working directory {}", target_cwd.display())
+            });
+
+            let mut command = process::Command::new(cmd);
+            command.args(args);
+            if opts.clear_env {
+                command.env_clear();
+            }
+            for key in &opts.env_unsets {
+                command.env_remove(key);
+            }
+            command.envs(opts.env_sets.iter().map(|(k, v)| (k, v)));
+
+            let err = command.exec();
+            eprintln!("failed to execute {}: {}", cmd, err);
+            process::exit(1);
+        }
+    };
+
+    loop {
+        let siginfo = match sigfd.read_signal() {
+            Ok(Some(info)) => info,
+            Ok(None) => continue,
+            Err(Errno::EINTR) => continue,
+            Err(e) => panic!("failed to read signalfd: {}", e),
+        };
+        match Signal::try_from(siginfo.ssi_signo as i32) {
+            Ok(Signal::SIGCHLD) => reap_children(child),
+            Ok(sig) => {
+                let _ = kill(unistd::Pid::from_raw(-child.as_raw()), sig);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Brings the loopback interface up in a fresh network namespace, where `lo`
+/// starts out down, so that IP connections to `localhost` (127.0.0.1, ::1)
+/// work under `--unshare-net`.
+fn bring_up_loopback() {
+    let sock = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )
+    .expect("failed to create socket for loopback configuration");
+
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(b"lo\0") {
+        *dst = *src as libc::c_char;
+    }
+
+    unsafe {
+        if libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS, &mut ifr) < 0 {
+            panic!("failed to get lo flags: {}", io::Error::last_os_error());
+        }
+        ifr.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        if libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &ifr) < 0 {
+            panic!("failed to bring lo up: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+/// Parsed command line: the mount namespace setup plus everything about how
+/// the final command is launched inside it.
+struct Options {
+    nixdir: PathBuf,
+    cmd: String,
+    args: Vec<String>,
+    mount_spec: Option<PathBuf>,
+    env_sets: Vec<(String, String)>,
+    env_unsets: Vec<String>,
+    clear_env: bool,
+    chdir: Option<PathBuf>,
+    unshare_net: bool,
+}
+
+fn run_chroot(opts: &Options) {
+    let nixdir = opts.nixdir.as_path();
+    let cmd = opts.cmd.as_str();
+    let args = opts.args.as_slice();
+    let mount_spec = opts.mount_spec.as_deref();
+
     let tempdir = TempDir::new().expect("failed to create temporary directory for mount point");
     let mut tempdir = WrapUmount::new(tempdir);
     let rootdir = PathBuf::from(tempdir.path());
@@ -132,7 +549,14 @@ fn run_chroot(nixdir: &Path, cmd: &str, args: &[String]) {
     let gid = unistd::getgid();
     // fixes issue #1 where writing to /proc/self/gid_map fails
     // see user_namespaces(7) for more documentation
-    unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER).expect("unshare failed");
+    let mut ns_flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWPID;
+    if opts.unshare_net {
+        ns_flags |= CloneFlags::CLONE_NEWNET;
+    }
+    unshare(ns_flags).expect("unshare failed");
+    if opts.unshare_net {
+        bring_up_loopback();
+    }
     if let Ok(mut file) = fs::File::create("/proc/self/setgroups") {
         let _ = file.write_all(b"deny");
     }
@@ -218,78 +642,9 @@ fn run_chroot(nixdir: &Path, cmd: &str, args: &[String]) {
             NONE,
         )
         .unwrap();
-        mount(
-            Some("none"),
-            "/nix/store",
-            Some("tmpfs"),
-            MsFlags::empty(),
-            Some("mode=0755"),
-        )
-        .unwrap();
-        let sroot = PathBuf::from("/nix/store");
-        for entry in fs::read_dir(&nixdir).unwrap() {
-            let entry = entry.unwrap();
-            let stat = entry.metadata().unwrap();
-            let name = entry.file_name();
-            let path = sroot.join(&name);
-            let store_path = entry.path();
-            if stat.is_dir() {
-                fs::create_dir(&path).unwrap();
-            } else if stat.is_file() {
-                fs::File::create(&path).unwrap();
-            } else if stat.file_type().is_symlink() {
-                let target = fs::read_link(&store_path).unwrap();
-                symlink(&target, &path).unwrap();
-            }
-            if stat.is_dir() || stat.is_file() {
-                mount(
-                    Some(&store_path),
-                    &path,
-                    Some("none"),
-                    MsFlags::MS_BIND | MsFlags::MS_REC,
-                    NONE,
-                )
-                .unwrap();
-            }
-        }
-        if let Ok(iter) = fs::read_dir(tmp.path()) {
-            for entry in iter {
-                let entry = entry.unwrap();
-                let name = entry.file_name();
-                let path = sroot.join(&name);
-                if path.exists() {
-                    continue;
-                }
-                let stat = entry.metadata().unwrap();
-                let store_path = entry.path();
-                if stat.is_dir() {
-                    fs::create_dir(&path).unwrap();
-                } else if stat.is_file() {
-                    fs::File::create(&path).unwrap();
-                } else if stat.file_type().is_symlink() {
-                    let target = fs::read_link(&store_path).unwrap();
-                    symlink(&target, &path).unwrap();
-                }
-                if stat.is_dir() || stat.is_file() {
-                    mount(
-                        Some(&store_path),
-                        &path,
-                        Some("none"),
-                        MsFlags::MS_BIND | MsFlags::MS_REC,
-                        NONE,
-                    )
-                    .unwrap();
-                }
-            }
+        if !overlay_supported() || mount_store_overlay(nixdir, tmp.path()).is_err() {
+            mount_store_legacy(nixdir, tmp.path());
         }
-        mount(
-            Some("none"),
-            "/nix/store",
-            Some("none"),
-            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
-            NONE,
-        )
-        .unwrap();
     } else {
         mount(
             Some("none"),
@@ -310,24 +665,113 @@ fn run_chroot(nixdir: &Path, cmd: &str, args: &[String]) {
         .unwrap();
     }
 
-    // restore cwd
-    env::set_current_dir(&cwd)
-        .unwrap_or_else(|_| panic!("cannot restore working directory {}", cwd.display()));
+    if let Some(spec_path) = mount_spec {
+        apply_mount_spec(spec_path);
+    }
 
-    let err = process::Command::new(cmd).args(args).exec();
+    // block termination signals here in the host namespace too: otherwise a
+    // SIGINT/SIGTERM hitting the foreground process group would kill this
+    // wrapper outright (default disposition) while the PID-1 init and
+    // command are still tearing down in the now-orphaned namespace, instead
+    // of letting the init forward the signal and drive our eventual exit.
+    let mut term_mask = SigSet::empty();
+    term_mask.add(Signal::SIGINT);
+    term_mask.add(Signal::SIGTERM);
+    term_mask.add(Signal::SIGHUP);
+    term_mask
+        .thread_block()
+        .expect("failed to block termination signals");
+
+    // the first fork after unsharing CLONE_NEWPID becomes PID 1 of the new
+    // namespace, so that child (not us) must act as its init; we just wait
+    // for it and mirror its eventual exit status.
+    let target_cwd = opts.chdir.as_deref().unwrap_or(&cwd);
+    match unsafe { unistd::fork() }.expect("failed to fork init process") {
+        unistd::ForkResult::Parent { child } => match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => process::exit(code),
+            Ok(WaitStatus::Signaled(_, sig, _)) => process::exit(128 + sig as i32),
+            Ok(_) => process::exit(1),
+            Err(e) => panic!("waitpid failed: {}", e),
+        },
+        unistd::ForkResult::Child => run_init(cmd, args, target_cwd, opts),
+    }
+}
 
-    eprintln!("failed to execute {}: {}", &cmd, err);
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--env KEY=VALUE]... [--unset KEY]... [--clearenv] [--chdir DIR] [--mounts FILE] [--unshare-net] [--] <nixpath> <command> [args...]",
+        program
+    );
     process::exit(1);
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <nixpath> <command>\n", args[0]);
-        process::exit(1);
+fn parse_args(raw: &[String]) -> Options {
+    let mut mount_spec = None;
+    let mut env_sets = Vec::new();
+    let mut env_unsets = Vec::new();
+    let mut clear_env = false;
+    let mut chdir = None;
+    let mut unshare_net = false;
+
+    let mut i = 1;
+    while i < raw.len() {
+        match raw[i].as_str() {
+            "--mounts" => {
+                i += 1;
+                let path = raw.get(i).unwrap_or_else(|| usage(&raw[0]));
+                mount_spec = Some(PathBuf::from(path));
+            }
+            "--env" => {
+                i += 1;
+                let kv = raw.get(i).unwrap_or_else(|| usage(&raw[0]));
+                let (key, value) = kv.split_once('=').unwrap_or_else(|| {
+                    eprintln!("--env expects KEY=VALUE");
+                    process::exit(1);
+                });
+                env_sets.push((key.to_string(), value.to_string()));
+            }
+            "--unset" => {
+                i += 1;
+                let key = raw.get(i).unwrap_or_else(|| usage(&raw[0]));
+                env_unsets.push(key.clone());
+            }
+            "--clearenv" => clear_env = true,
+            "--chdir" => {
+                i += 1;
+                let dir = raw.get(i).unwrap_or_else(|| usage(&raw[0]));
+                chdir = Some(PathBuf::from(dir));
+            }
+            "--unshare-net" => unshare_net = true,
+            "--" => {
+                i += 1;
+                break;
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+
+    if raw.len() < i + 2 {
+        usage(&raw[0]);
+    }
+    let nixdir = fs::canonicalize(&raw[i])
+        .unwrap_or_else(|_| panic!("failed to resolve nix directory {}", &raw[i]));
+
+    Options {
+        nixdir,
+        cmd: raw[i + 1].clone(),
+        args: raw[i + 2..].to_vec(),
+        mount_spec,
+        env_sets,
+        env_unsets,
+        clear_env,
+        chdir,
+        unshare_net,
     }
-    let nixdir = fs::canonicalize(&args[1])
-        .unwrap_or_else(|_| panic!("failed to resolve nix directory {}", &args[1]));
+}
 
-    run_chroot(&nixdir, &args[2], &args[3..]);
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = parse_args(&args);
+    run_chroot(&opts);
 }